@@ -5,11 +5,32 @@
 /// General actor system infrastructure.
 
 use std::any::{Any, AnyRefExt, AnyMutRefExt};
-use std::collections::hashmap::HashMap;
-use std::io::TcpStream;
+use std::cell::RefCell;
+use std::collections::hashmap::{HashMap, HashSet};
 use std::mem::{transmute, transmute_copy};
 use std::raw::TraitObject;
+use collections::treemap::TreeMap;
 use serialize::json;
+use serialize::json::{Json, ToJson};
+use sync::{Arc, Mutex};
+
+use devtools_traits::DevtoolScriptControlMsg;
+use servo_msg::constellation_msg::PipelineId;
+
+use actors::tab::TabActor;
+use protocol::{JsonPacketSender, Transport};
+
+/// Identifies a live client connection. Assigned when it's accepted, and used to route
+/// unsolicited actor events (see `ActorRegistry::emit`) back to whichever connections
+/// have subscribed to them.
+pub type Token = uint;
+
+/// A connection shared between the task that reads requests off it and any actor that
+/// wants to push an unsolicited event onto it.
+pub type Conn = Arc<Mutex<Transport>>;
+
+/// All of the connections a server is currently handling, keyed by `Token`.
+pub type Connections = Arc<Mutex<HashMap<Token, Conn>>>;
 
 /// A common trait for all devtools actors that encompasses an immutable name
 /// and the ability to process messages that are directed to particular actors.
@@ -19,8 +40,27 @@ pub trait Actor: Any {
                       registry: &ActorRegistry,
                       msg_type: &String,
                       msg: &json::Object,
-                      stream: &mut TcpStream) -> bool;
+                      token: Token,
+                      transport: &mut Transport) -> bool;
     fn name(&self) -> String;
+
+    /// Handle a message forwarded from this actor's script task, e.g. a console.log
+    /// call or a page error. Most actors don't originate from a script task and can
+    /// leave this as a no-op.
+    fn handle_script_message(&self, _registry: &ActorRegistry, _msg: DevtoolScriptControlMsg) {
+    }
+
+    /// Handle a bulk packet addressed to this actor. Actors that don't deal in raw
+    /// bytes (screenshots, heap snapshots, large sources, ...) can leave this as the
+    /// default, which reports the bulk type as unsupported.
+    fn handle_bulk(&self,
+                   _registry: &ActorRegistry,
+                   _type_: &String,
+                   _data: Vec<u8>,
+                   _token: Token,
+                   _transport: &mut Transport) -> bool {
+        false
+    }
 }
 
 impl<'a> AnyMutRefExt<'a> for &'a mut Actor {
@@ -69,13 +109,32 @@ impl<'a> AnyRefExt<'a> for &'a Actor {
 /// A list of known, owned actors.
 pub struct ActorRegistry {
     actors: HashMap<String, Box<Actor+Send+Sized>>,
+    connections: Connections,
+    /// Per-actor-name set of connections that have subscribed to that actor's
+    /// unsolicited events, e.g. via a `startListeners` request.
+    listeners: RefCell<HashMap<String, Vec<Token>>>,
+    /// Which console actor owns a given script task's pipeline, so that messages
+    /// arriving from that script task can be routed to the right actor.
+    console_for_pipeline: RefCell<HashMap<PipelineId, String>>,
+    /// Which tab actor represents a given pipeline, so that a navigation reported
+    /// on that pipeline can be turned into a `tabNavigated` event from the right actor.
+    tab_for_pipeline: RefCell<HashMap<PipelineId, String>>,
+    /// Connections whose `clientVersion` request asked for an unsupported protocol
+    /// generation. `handle_message` refuses to dispatch anything else for them, to
+    /// any actor, until they retry `clientVersion` and succeed.
+    rejected: RefCell<HashSet<Token>>,
 }
 
 impl ActorRegistry {
-    /// Create an empty registry.
-    pub fn new() -> ActorRegistry {
+    /// Create an empty registry that can push unsolicited events to `connections`.
+    pub fn new(connections: Connections) -> ActorRegistry {
         ActorRegistry {
             actors: HashMap::new(),
+            connections: connections,
+            listeners: RefCell::new(HashMap::new()),
+            console_for_pipeline: RefCell::new(HashMap::new()),
+            tab_for_pipeline: RefCell::new(HashMap::new()),
+            rejected: RefCell::new(HashSet::new()),
         }
     }
 
@@ -84,6 +143,94 @@ impl ActorRegistry {
         self.actors.insert(actor.name().to_string(), actor);
     }
 
+    /// Note that `actor_name` is the console actor that should receive messages
+    /// forwarded from `pipeline`'s script task.
+    pub fn register_console(&self, pipeline: PipelineId, actor_name: String) {
+        self.console_for_pipeline.borrow_mut().insert(pipeline, actor_name);
+    }
+
+    /// Note that `actor_name` is the tab actor representing `pipeline`.
+    pub fn register_tab(&self, pipeline: PipelineId, actor_name: String) {
+        self.tab_for_pipeline.borrow_mut().insert(pipeline, actor_name);
+    }
+
+    /// Subscribe `token`'s connection to unsolicited events emitted by `actor_name`.
+    pub fn start_listening(&self, actor_name: &str, token: Token) {
+        let mut listeners = self.listeners.borrow_mut();
+        listeners.find_or_insert_with(actor_name.to_string(), |_| vec!()).push(token);
+    }
+
+    /// Unsubscribe `token`'s connection from `actor_name`'s unsolicited events.
+    pub fn stop_listening(&self, actor_name: &str, token: Token) {
+        let mut listeners = self.listeners.borrow_mut();
+        if let Some(tokens) = listeners.find_mut(&actor_name.to_string()) {
+            tokens.retain(|&t| t != token);
+        }
+    }
+
+    /// Mark `token`'s connection as having failed `clientVersion` negotiation. Checked
+    /// by `handle_message` before dispatching to any actor, so a connection that asked
+    /// for an unsupported protocol generation can't fall back to using `tab`/`console`
+    /// actors instead.
+    pub fn reject_version(&self, token: Token) {
+        self.rejected.borrow_mut().insert(token);
+    }
+
+    /// Clear a prior `reject_version`, e.g. once `token` negotiates a supported version.
+    pub fn accept_version(&self, token: Token) {
+        self.rejected.borrow_mut().remove(&token);
+    }
+
+    /// Drop `token` from every actor's listener list and any other per-connection
+    /// state kept for it, e.g. once its connection closes.
+    pub fn forget_connection(&self, token: Token) {
+        for (_name, tokens) in self.listeners.borrow_mut().iter_mut() {
+            tokens.retain(|&t| t != token);
+        }
+        self.rejected.borrow_mut().remove(&token);
+    }
+
+    /// Write an unsolicited packet to every connection currently subscribed to
+    /// `actor_name`'s events, skipping any that failed `clientVersion` negotiation —
+    /// the same "nothing else is served" guarantee `refuse_rejected` enforces for
+    /// inbound requests applies to outbound events too.
+    pub fn emit<T: ToJson>(&self, actor_name: &str, msg: &T) {
+        let tokens = match self.listeners.borrow().find(&actor_name.to_string()) {
+            Some(tokens) => tokens.clone(),
+            None => return,
+        };
+        let connections = self.connections.lock();
+        let rejected = self.rejected.borrow();
+        for token in tokens.iter() {
+            if rejected.contains(token) {
+                continue;
+            }
+            if let Some(conn) = connections.find(token) {
+                let _ = conn.lock().write_json_packet(msg);
+            }
+        }
+    }
+
+    /// Route a message that arrived from a script task's `DevtoolScriptControlMsg`
+    /// channel to the console actor that owns its pipeline.
+    pub fn deliver_script_message(&self, pipeline: PipelineId, msg: DevtoolScriptControlMsg) {
+        let actor_name = match self.console_for_pipeline.borrow().find(&pipeline) {
+            Some(actor_name) => actor_name.clone(),
+            None => return,
+        };
+        self.actors.find(&actor_name).unwrap().handle_script_message(self, msg);
+    }
+
+    /// Report that `pipeline` finished navigating to `url`, emitting a `tabNavigated`
+    /// event from the tab actor that represents it.
+    pub fn navigate_tab(&self, pipeline: PipelineId, url: String) {
+        let actor_name = match self.tab_for_pipeline.borrow().find(&pipeline) {
+            Some(actor_name) => actor_name.clone(),
+            None => return,
+        };
+        self.find::<TabActor>(actor_name.as_slice()).navigated(self, url);
+    }
+
     /// Find an actor by registered name
     pub fn find<'a, T: 'static>(&'a self, name: &str) -> &'a T {
         //FIXME: Rust bug forces us to implement bogus Any for Actor since downcast_ref currently
@@ -104,17 +251,74 @@ impl ActorRegistry {
 
     /// Attempt to process a message as directed by its `to` property. If the actor is not
     /// found or does not indicate that it knew how to process the message, ignore the failure.
-    pub fn handle_message(&self, msg: &json::Object, stream: &mut TcpStream) {
-        let to = msg.find(&"to".to_string()).unwrap().as_string().unwrap();
-        match self.actors.find(&to.to_string()) {
-            None => println!("message received for unknown actor \"{:s}\"", to),
-            Some(actor) => {
-                let msg_type = msg.find(&"type".to_string()).unwrap().as_string().unwrap();
-                if !actor.handle_message(self, &msg_type.to_string(), msg, stream) {
+    pub fn handle_message(&self, msg: &json::Object, token: Token, transport: &mut Transport) {
+        let to = match msg.find(&"to".to_string()).and_then(|v| v.as_string()) {
+            Some(to) => to,
+            None => {
+                println!("message received with no \"to\" property");
+                return;
+            }
+        };
+        let msg_type = msg.find(&"type".to_string()).and_then(|v| v.as_string());
+
+        if self.refuse_rejected(to, msg_type, token, transport) {
+            return;
+        }
+
+        match (self.actors.find(&to.to_string()), msg_type) {
+            (None, _) => println!("message received for unknown actor \"{:s}\"", to),
+            (Some(_), None) => println!("message received for actor \"{:s}\" without a type", to),
+            (Some(actor), Some(msg_type)) => {
+                if !actor.handle_message(self, &msg_type.to_string(), msg, token, transport) {
                     println!("unexpected message type \"{:s}\" found for actor \"{:s}\"",
                              msg_type, to);
                 }
             }
         }
     }
+
+    /// Attempt to process a bulk packet as directed by its leading `actor` name.
+    pub fn handle_bulk(&self,
+                       actor: &str,
+                       type_: &String,
+                       data: Vec<u8>,
+                       token: Token,
+                       transport: &mut Transport) {
+        if self.refuse_rejected(actor, Some(type_.as_slice()), token, transport) {
+            return;
+        }
+
+        match self.actors.find(&actor.to_string()) {
+            None => println!("bulk packet received for unknown actor \"{:s}\"", actor),
+            Some(a) => {
+                if !a.handle_bulk(self, type_, data, token, transport) {
+                    println!("unexpected bulk type \"{:s}\" found for actor \"{:s}\"",
+                             type_, actor);
+                }
+            }
+        }
+    }
+
+    /// Shared by `handle_message` and `handle_bulk`: if `token` failed `clientVersion`
+    /// negotiation, refuse to dispatch to `to` (unless it's the `clientVersion` retry
+    /// itself) and write back a `protocolVersionMismatch` error. Returns whether the
+    /// caller should stop dispatching.
+    fn refuse_rejected(&self,
+                       to: &str,
+                       msg_type: Option<&str>,
+                       token: Token,
+                       transport: &mut Transport) -> bool {
+        if !self.rejected.borrow().contains(&token) || (to == "root" && msg_type == Some("clientVersion")) {
+            return false;
+        }
+
+        let mut reply: json::Object = TreeMap::new();
+        reply.insert("from".to_string(), to.to_json());
+        reply.insert("error".to_string(), "protocolVersionMismatch".to_json());
+        reply.insert("message".to_string(),
+                      "no further requests are served until the client renegotiates \
+                       a supported clientVersion".to_json());
+        let _ = transport.write_json_packet(&Json::Object(reply));
+        true
+    }
 }
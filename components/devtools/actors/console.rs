@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Liberally derived from the [Firefox JS implementation]
+/// (http://mxr.mozilla.org/mozilla-central/source/toolkit/devtools/server/actors/webconsole.js).
+
+use actor::{Actor, ActorRegistry, Token};
+use protocol::{JsonPacketSender, Transport};
+
+use devtools_traits::DevtoolScriptControlMsg;
+use servo_msg::constellation_msg::PipelineId;
+
+use collections::treemap::TreeMap;
+use serialize::json;
+use serialize::json::{Json, ToJson};
+
+/// The messages `ConsoleActor` understands: subscribe or unsubscribe to the
+/// events `handle_script_message` below emits. Every script message, page
+/// errors included, is emitted as a `consoleAPICall`; no `pageError` event is sent.
+/// Firefox's console actor uses the same request for both console API calls
+/// and page errors, keyed by the `listeners` array in the real request; we
+/// only have one kind of subscriber here so ignore it. Like `TabActor`'s
+/// listener messages, this leans on `#[derive(ActorMessage)]` for the
+/// `msg_type` dispatch and wire reply instead of hand-matching strings.
+#[derive(ActorMessage)]
+enum ConsoleMessage {
+    #[msg_type = "startListeners"]
+    StartListeners,
+    #[msg_type = "stopListeners"]
+    StopListeners,
+}
+
+/// An actor representing a console, forwarding console.log-style messages and page
+/// errors arriving from its script task on to whatever clients have started listening.
+pub struct ConsoleActor {
+    pub name: String,
+    pub script_chan: Sender<DevtoolScriptControlMsg>,
+    pub pipeline: PipelineId,
+}
+
+impl Actor for ConsoleActor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn handle_message(&self,
+                      registry: &ActorRegistry,
+                      msg_type: &String,
+                      _msg: &json::Object,
+                      token: Token,
+                      transport: &mut Transport) -> bool {
+        let message = match ConsoleMessage::parse(msg_type.as_slice()) {
+            Some(message) => message,
+            None => return false,
+        };
+
+        match message {
+            ConsoleMessage::StartListeners => registry.start_listening(self.name.as_slice(), token),
+            ConsoleMessage::StopListeners => registry.stop_listening(self.name.as_slice(), token),
+        }
+
+        transport.write_json_packet(&message.reply(self.name.as_slice())).is_ok()
+    }
+
+    fn handle_script_message(&self, registry: &ActorRegistry, msg: DevtoolScriptControlMsg) {
+        // TODO: once devtools_traits carries structured console messages (log level,
+        // source location, etc.) distinguish "consoleAPICall" from "pageError" here
+        // instead of forwarding everything as a console API call.
+        let mut packet: json::Object = TreeMap::new();
+        packet.insert("from".to_string(), self.name.to_json());
+        packet.insert("type".to_string(), "consoleAPICall".to_json());
+        packet.insert("message".to_string(), format!("{:?}", msg).to_json());
+        registry.emit(self.name.as_slice(), &Json::Object(packet));
+    }
+}
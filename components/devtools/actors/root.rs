@@ -0,0 +1,130 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Liberally derived from the [Firefox JS implementation]
+/// (http://mxr.mozilla.org/mozilla-central/source/toolkit/devtools/server/actors/root.js).
+
+use actor::{Actor, ActorRegistry, Token};
+use protocol::{JsonPacketSender, Transport};
+
+use collections::treemap::TreeMap;
+use serialize::json;
+use serialize::json::{Json, ToJson};
+
+// Unlike `TabActor`/`ConsoleActor`, `RootActor`'s messages aren't converted to
+// `#[derive(ActorMessage)]`: each reply here carries its own payload (negotiated
+// version or an error, the capabilities descriptor, the tab list) rather than the
+// bare `{"from": ..., "type": ...}` the derive generates, so there's no boilerplate
+// for it to remove below.
+
+/// The protocol generation this server speaks. Bumped whenever a wire-incompatible
+/// change is made to the actors below; clients that send a `clientVersion` request
+/// with something newer are downgraded to this one; something older is accepted as
+/// is unless it's below `MIN_PROTOCOL_VERSION`, in which case it's rejected outright.
+pub static PROTOCOL_VERSION: uint = 1;
+
+/// Oldest `clientVersion` this server still knows how to talk to.
+static MIN_PROTOCOL_VERSION: uint = 1;
+
+/// The actor that greets a newly-connected client and lets it discover what else
+/// this server supports before it starts poking at any particular tab.
+pub struct RootActor {
+    pub next: uint,
+    pub tabs: Vec<String>,
+}
+
+impl RootActor {
+    pub fn new(next: uint, tabs: Vec<String>) -> RootActor {
+        RootActor {
+            next: next,
+            tabs: tabs,
+        }
+    }
+
+    /// The greeting sent to a client immediately after it connects, advertising the
+    /// protocol generation and the feature set this server implements.
+    pub fn encodable(&self) -> Json {
+        let mut reply: json::Object = TreeMap::new();
+        reply.insert("from".to_string(), "root".to_json());
+        reply.insert("applicationType".to_string(), "servo".to_json());
+        reply.insert("protocolVersion".to_string(), PROTOCOL_VERSION.to_json());
+        reply.insert("traits".to_string(), self.capabilities());
+        Json::Object(reply)
+    }
+
+    /// Enumerates the actor types and optional features a `getCapabilities` request
+    /// can ask about.
+    fn capabilities(&self) -> Json {
+        let mut actor_types: json::Object = TreeMap::new();
+        actor_types.insert("root".to_string(), true.to_json());
+        actor_types.insert("tab".to_string(), true.to_json());
+        actor_types.insert("console".to_string(), true.to_json());
+
+        let mut traits: json::Object = TreeMap::new();
+        traits.insert("actorTypes".to_string(), Json::Object(actor_types));
+        traits.insert("bulkData".to_string(), true.to_json());
+        traits.insert("webSocket".to_string(), true.to_json());
+        traits.insert("eventSubscription".to_string(), true.to_json());
+        Json::Object(traits)
+    }
+}
+
+impl Actor for RootActor {
+    fn name(&self) -> String {
+        "root".to_string()
+    }
+
+    fn handle_message(&self,
+                      registry: &ActorRegistry,
+                      msg_type: &String,
+                      msg: &json::Object,
+                      token: Token,
+                      transport: &mut Transport) -> bool {
+        match msg_type.as_slice() {
+            "clientVersion" => {
+                let requested = msg.find(&"protocolVersion".to_string())
+                                   .and_then(|v| v.as_u64())
+                                   .unwrap_or(PROTOCOL_VERSION as u64) as uint;
+
+                let mut reply: json::Object = TreeMap::new();
+                reply.insert("from".to_string(), self.name().to_json());
+
+                if requested < MIN_PROTOCOL_VERSION {
+                    registry.reject_version(token);
+                    reply.insert("error".to_string(), "protocolVersionMismatch".to_json());
+                    reply.insert("message".to_string(),
+                                 format!("client speaks protocol {}, server requires at least {}",
+                                         requested, MIN_PROTOCOL_VERSION).to_json());
+                    println!("devtools client rejected: protocol version {} < {}",
+                             requested, MIN_PROTOCOL_VERSION);
+                } else {
+                    // Downgrade to whichever of the two generations is older.
+                    registry.accept_version(token);
+                    let negotiated = if requested < PROTOCOL_VERSION { requested } else { PROTOCOL_VERSION };
+                    reply.insert("protocolVersion".to_string(), negotiated.to_json());
+                    println!("devtools client negotiated protocol version {}", negotiated);
+                }
+
+                transport.write_json_packet(&Json::Object(reply)).is_ok()
+            }
+
+            "getCapabilities" => {
+                let mut reply: json::Object = TreeMap::new();
+                reply.insert("from".to_string(), self.name().to_json());
+                reply.insert("traits".to_string(), self.capabilities());
+                transport.write_json_packet(&Json::Object(reply)).is_ok()
+            }
+
+            "listTabs" => {
+                let mut reply: json::Object = TreeMap::new();
+                reply.insert("from".to_string(), self.name().to_json());
+                reply.insert("tabs".to_string(),
+                              self.tabs.iter().map(|t| t.to_json()).collect::<Vec<Json>>().to_json());
+                transport.write_json_packet(&Json::Object(reply)).is_ok()
+            }
+
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Liberally derived from the [Firefox JS implementation]
+/// (http://mxr.mozilla.org/mozilla-central/source/toolkit/devtools/server/actors/webbrowser.js).
+
+use actor::{Actor, ActorRegistry, Token};
+use protocol::{JsonPacketSender, Transport};
+
+use collections::treemap::TreeMap;
+use serialize::json;
+use serialize::json::{Json, ToJson};
+
+/// The messages `TabActor` understands: subscribe or unsubscribe to the
+/// `tabNavigated` event that `navigated` below emits. That's the same
+/// subscribe/unsubscribe shape `ConsoleActor` exposes for its own events, so
+/// this also leans on `#[derive(ActorMessage)]` rather than hand-matching
+/// the two `msg_type` strings.
+#[derive(ActorMessage)]
+enum TabMessage {
+    #[msg_type = "startListeners"]
+    StartListeners,
+    #[msg_type = "stopListeners"]
+    StopListeners,
+}
+
+/// An actor representing a browser tab.
+pub struct TabActor {
+    pub name: String,
+    pub title: String,
+    pub url: String,
+}
+
+impl Actor for TabActor {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn handle_message(&self,
+                      registry: &ActorRegistry,
+                      msg_type: &String,
+                      _msg: &json::Object,
+                      token: Token,
+                      transport: &mut Transport) -> bool {
+        let message = match TabMessage::parse(msg_type.as_slice()) {
+            Some(message) => message,
+            None => return false,
+        };
+
+        match message {
+            TabMessage::StartListeners => registry.start_listening(self.name.as_slice(), token),
+            TabMessage::StopListeners => registry.stop_listening(self.name.as_slice(), token),
+        }
+
+        transport.write_json_packet(&message.reply(self.name.as_slice())).is_ok()
+    }
+}
+
+impl TabActor {
+    /// Emit a `tabNavigated` event to every connection listening to this tab, e.g.
+    /// once the constellation reports the pipeline finished navigating to `url`.
+    pub fn navigated(&self, registry: &ActorRegistry, url: String) {
+        let mut packet: json::Object = TreeMap::new();
+        packet.insert("from".to_string(), self.name.to_json());
+        packet.insert("type".to_string(), "tabNavigated".to_json());
+        packet.insert("url".to_string(), url.to_json());
+        registry.emit(self.name.as_slice(), &Json::Object(packet));
+    }
+}
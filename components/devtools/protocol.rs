@@ -0,0 +1,410 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Wire-level framing for devtools clients.
+///
+/// Two transports are understood: the colon-delimited stream transport
+/// described at https://wiki.mozilla.org/Remote_Debugging_Protocol_Stream_Transport
+/// (`[ascii length]:[JSON data]`) used by the `devtools` command-line and
+/// `adb forward`-based tools, and a WebSocket transport (RFC 6455) used by
+/// browser-based and WebSocket-only debugging frontends. `Transport::new`
+/// sniffs the first bytes of a freshly-accepted connection to decide which
+/// one a given client speaks.
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use serialize::base64::{STANDARD, ToBase64};
+use serialize::json;
+use serialize::json::ToJson;
+use std::cmp;
+use std::io::{BufferedStream, IoError, IoResult, OtherIoError, TcpStream};
+use std::num;
+
+/// The GUID fixed by RFC 6455 section 1.3, concatenated onto the client's
+/// `Sec-WebSocket-Key` before hashing to produce `Sec-WebSocket-Accept`.
+static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A `TcpStream` with a handful of already-read bytes prepended, so that the
+/// bytes used to sniff which transport a connection wants can still be read
+/// back out as part of the stream.
+struct PeekedStream {
+    peeked: Vec<u8>,
+    pos: uint,
+    stream: TcpStream,
+}
+
+impl Clone for PeekedStream {
+    fn clone(&self) -> PeekedStream {
+        PeekedStream {
+            peeked: self.peeked.clone(),
+            pos: self.pos,
+            stream: self.stream.clone(),
+        }
+    }
+}
+
+impl Reader for PeekedStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.pos < self.peeked.len() {
+            let n = cmp::min(buf.len(), self.peeked.len() - self.pos);
+            buf.slice_to_mut(n).clone_from_slice(self.peeked.slice(self.pos, self.pos + n));
+            self.pos += n;
+            Ok(n)
+        } else {
+            self.stream.read(buf)
+        }
+    }
+}
+
+impl Writer for PeekedStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+/// A packet read off a `Transport`. Most packets are ordinary JSON, but the stream
+/// transport also allows *bulk* packets, which carry raw bytes (screenshots, heap
+/// dumps, large sources) without base64-inflating them into a JSON string first. See
+/// https://wiki.mozilla.org/Remote_Debugging_Protocol_Stream_Transport#Bulk_data
+pub enum Packet {
+    Json(json::Object),
+    Bulk { actor: String, type_: String, data: Vec<u8> },
+}
+
+/// The framing negotiated with a connected client.
+pub enum Transport {
+    /// The plain colon-delimited stream transport over a bare TCP socket.
+    Tcp(BufferedStream<PeekedStream>),
+    /// A WebSocket connection upgraded from an HTTP handshake.
+    Ws(BufferedStream<PeekedStream>),
+}
+
+impl Transport {
+    /// Sniff the first bytes of `stream` and return the transport that the
+    /// client on the other end wants, performing the WebSocket handshake if
+    /// the request line starts with `GET` *and* its headers carry
+    /// `Upgrade: websocket`. A `GET` without that header is reported as
+    /// malformed rather than treated as either transport.
+    pub fn new(mut stream: TcpStream) -> IoResult<Transport> {
+        let mut peeked = vec!(0u8; 4);
+        try!(stream.read_at_least(4, peeked.as_mut_slice()));
+
+        let maybe_http = peeked.as_slice() == b"GET ";
+
+        let peeked = PeekedStream {
+            peeked: peeked,
+            pos: 0,
+            stream: stream,
+        };
+        let mut stream = BufferedStream::new(peeked);
+
+        if maybe_http {
+            // A `GET ` alone isn't enough to know this is a WebSocket client: only
+            // treat it as one if the headers actually carry the upgrade Firefox's
+            // WebSocket-only frontends send, instead of unconditionally running the
+            // handshake (and failing on the missing `Sec-WebSocket-Key`) for any
+            // plain HTTP-looking request.
+            let headers = try!(read_headers(&mut stream));
+            if header(headers.as_slice(), "upgrade").map_or(false, |v| v.eq_ignore_ascii_case("websocket")) {
+                try!(do_handshake(&mut stream, headers.as_slice()));
+                Ok(Ws(stream))
+            } else {
+                Err(bad_data())
+            }
+        } else {
+            Ok(Tcp(stream))
+        }
+    }
+
+    /// Read a single packet, blocking until a complete one has arrived. Bulk packets
+    /// are only recognized on the `Tcp` transport: every WebSocket frame read is parsed
+    /// as JSON, so a bulk packet framed the way `write_bulk_packet` sends one over
+    /// WebSocket is reported as malformed.
+    pub fn read_packet(&mut self) -> IoResult<Packet> {
+        match *self {
+            Tcp(ref mut stream) => read_colon_packet(stream),
+            Ws(ref mut stream) => {
+                let payload = try!(read_ws_frame(stream));
+                let text = try!(String::from_utf8(payload).map_err(|_| bad_data()));
+                let json = try!(json::from_str(text.as_slice()).map_err(|_| bad_data()));
+                Ok(Packet::Json(try!(json.as_object().ok_or_else(bad_data)).clone()))
+            }
+        }
+    }
+
+    /// Try to clone the underlying connection so a copy can be handed to
+    /// another task (e.g. to stream unsolicited actor events).
+    pub fn try_clone(&self) -> IoResult<Transport> {
+        Ok(match *self {
+            Tcp(ref stream) => Tcp(BufferedStream::new(stream.get_ref().clone())),
+            Ws(ref stream) => Ws(BufferedStream::new(stream.get_ref().clone())),
+        })
+    }
+
+    /// Force a blocked read on this connection to wake up and fail, so a task parked
+    /// in `read_packet` can notice the server is shutting down.
+    pub fn close_read(&mut self) -> IoResult<()> {
+        match *self {
+            Tcp(ref mut stream) => stream.get_mut().stream.close_read(),
+            Ws(ref mut stream) => stream.get_mut().stream.close_read(),
+        }
+    }
+}
+
+fn bad_data() -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "malformed devtools packet",
+        detail: None,
+    }
+}
+
+fn read_colon_packet<R: Reader>(stream: &mut R) -> IoResult<Packet> {
+    // Both packet kinds share the same header shape: some ASCII up to (but not
+    // including) a single `:`, then a binary-safe payload whose length the header
+    // specifies. Only the shape of the header differs: `<length>` for JSON packets,
+    // `bulk <actor-name> <type> <length>` for bulk ones.
+    let mut header = vec!();
+    let colon = ':' as u8;
+    loop {
+        match try!(stream.read_byte()) {
+            c if c != colon => header.push(c),
+            _ => break,
+        }
+    }
+    let header = try!(String::from_utf8(header).map_err(|_| bad_data()));
+
+    if header.as_slice().starts_with("bulk ") {
+        let mut parts = header.as_slice().slice_from("bulk ".len()).split(' ');
+        let actor = try!(parts.next().ok_or_else(bad_data)).to_string();
+        let type_ = try!(parts.next().ok_or_else(bad_data)).to_string();
+        let len_str = try!(parts.next().ok_or_else(bad_data));
+        let len = try!(num::from_str_radix(len_str, 10).ok_or_else(bad_data));
+
+        // A zero-length bulk packet is legal (e.g. an empty heap snapshot); just
+        // don't bother reading from the stream for it.
+        let data = if len == 0 { vec!() } else { try!(stream.read_exact(len)) };
+        Ok(Packet::Bulk { actor: actor, type_: type_, data: data })
+    } else {
+        let packet_len = try!(num::from_str_radix(header.as_slice(), 10).ok_or_else(bad_data));
+        let packet_buf = try!(stream.read_exact(packet_len));
+        let packet = try!(String::from_utf8(packet_buf).map_err(|_| bad_data()));
+        let json = try!(json::from_str(packet.as_slice()).map_err(|_| bad_data()));
+        Ok(Packet::Json(try!(json.as_object().ok_or_else(bad_data)).clone()))
+    }
+}
+
+/// Read everything up to and including the trailing CRLFCRLF of an HTTP request
+/// (request line included, though it never contains a `:` and so is dropped),
+/// pairing each header's name with its value.
+fn read_headers(stream: &mut BufferedStream<PeekedStream>) -> IoResult<Vec<(String, String)>> {
+    let mut headers = vec!();
+    loop {
+        let line = try!(stream.read_line());
+        let line = line.as_slice().trim_right();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(1, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => headers.push((name.to_string(), value.trim().to_string())),
+            _ => {}
+        }
+    }
+    Ok(headers)
+}
+
+/// Look up a header by name, case-insensitively, as `read_headers` does not
+/// normalize case itself.
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+           .find(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+           .map(|&(_, ref v)| v.as_slice())
+}
+
+/// Perform the opening handshake for RFC 6455 against an already-parsed header set.
+fn do_handshake(stream: &mut BufferedStream<PeekedStream>, headers: &[(String, String)]) -> IoResult<()> {
+    let key = try!(header(headers, "sec-websocket-key").ok_or_else(bad_data));
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(WS_GUID);
+    let mut digest = [0u8, ..20];
+    sha1.result(digest.as_mut_slice());
+    let accept = digest.as_slice().to_base64(STANDARD);
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {:s}\r\n\r\n", accept);
+    try!(stream.write_str(response.as_slice()));
+    stream.flush()
+}
+
+/// WebSocket opcodes we care about. See RFC 6455 section 5.2.
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+
+fn read_ws_frame<R: Reader>(stream: &mut R) -> IoResult<Vec<u8>> {
+    let b0 = try!(stream.read_byte());
+    let opcode = b0 & 0x0f;
+
+    let b1 = try!(stream.read_byte());
+    let masked = (b1 & 0x80) != 0;
+    let mut len = (b1 & 0x7f) as u64;
+    if len == 126 {
+        len = try!(stream.read_be_u16()) as u64;
+    } else if len == 127 {
+        len = try!(stream.read_be_u64());
+    }
+
+    // Clients are required by RFC 6455 to mask every frame they send.
+    let mask = if masked {
+        let mut m = [0u8, ..4];
+        try!(stream.read_at_least(4, m.as_mut_slice()));
+        Some(m)
+    } else {
+        None
+    };
+
+    let mut data = try!(stream.read_exact(len as uint));
+    if let Some(mask) = mask {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    if opcode == OP_CLOSE {
+        return Err(bad_data());
+    }
+    Ok(data)
+}
+
+fn write_ws_frame<W: Writer>(stream: &mut W, opcode: u8, payload: &[u8]) -> IoResult<()> {
+    try!(stream.write_u8(0x80 | opcode));
+    let len = payload.len();
+    if len < 126 {
+        try!(stream.write_u8(len as u8));
+    } else if len < 65536 {
+        try!(stream.write_u8(126));
+        try!(stream.write_be_u16(len as u16));
+    } else {
+        try!(stream.write_u8(127));
+        try!(stream.write_be_u64(len as u64));
+    }
+    // The server-to-client direction is never masked.
+    try!(stream.write(payload));
+    stream.flush()
+}
+
+/// Writes packets onto whichever transport a client negotiated. JSON packets are
+/// built from anything `ToJson`, rather than `#[deriving(Encodable)]` structs, since
+/// actor replies and events need a `type` key and `type` is a reserved word.
+pub trait JsonPacketSender {
+    fn write_json_packet<T: ToJson>(&mut self, obj: &T) -> IoResult<()>;
+
+    /// Write a bulk packet carrying `data` verbatim, addressed to `actor`'s `type_`
+    /// handler. `data` may be empty.
+    fn write_bulk_packet(&mut self, actor: &str, type_: &str, data: &[u8]) -> IoResult<()>;
+}
+
+impl JsonPacketSender for Transport {
+    fn write_json_packet<T: ToJson>(&mut self, obj: &T) -> IoResult<()> {
+        let s = obj.to_json().to_string();
+        match *self {
+            Tcp(ref mut stream) => {
+                try!(stream.write_str(s.len().to_string().as_slice()));
+                try!(stream.write_u8(':' as u8));
+                try!(stream.write_str(s.as_slice()));
+                stream.flush()
+            }
+            Ws(ref mut stream) => write_ws_frame(stream, OP_TEXT, s.into_bytes().as_slice()),
+        }
+    }
+
+    fn write_bulk_packet(&mut self, actor: &str, type_: &str, data: &[u8]) -> IoResult<()> {
+        let header = format!("bulk {:s} {:s} {}", actor, type_, data.len());
+        match *self {
+            Tcp(ref mut stream) => {
+                try!(stream.write_str(header.as_slice()));
+                try!(stream.write_u8(':' as u8));
+                try!(stream.write(data));
+                stream.flush()
+            }
+            Ws(ref mut stream) => {
+                let mut payload = header.into_bytes();
+                payload.push(':' as u8);
+                payload.push_all(data);
+                write_ws_frame(stream, OP_BINARY, payload.as_slice())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_colon_packet, read_ws_frame, write_ws_frame, OP_TEXT, Packet};
+    use std::io::{MemReader, MemWriter};
+
+    #[test]
+    fn colon_packet_reads_json() {
+        let mut reader = MemReader::new(b"10:{\"a\":true}".to_vec());
+        match read_colon_packet(&mut reader).unwrap() {
+            Packet::Json(obj) => assert_eq!(obj.len(), 1),
+            Packet::Bulk { .. } => panic!("expected a JSON packet"),
+        }
+    }
+
+    #[test]
+    fn colon_packet_reads_bulk() {
+        let mut reader = MemReader::new(b"bulk console1 screenshot 3:abc".to_vec());
+        match read_colon_packet(&mut reader).unwrap() {
+            Packet::Bulk { actor, type_, data } => {
+                assert_eq!(actor.as_slice(), "console1");
+                assert_eq!(type_.as_slice(), "screenshot");
+                assert_eq!(data.as_slice(), b"abc");
+            }
+            Packet::Json(_) => panic!("expected a bulk packet"),
+        }
+    }
+
+    #[test]
+    fn colon_packet_reads_zero_length_bulk() {
+        // The colon terminating the header must not be swallowed as part of the
+        // numeric length, and a zero-length payload shouldn't try to read anything.
+        let mut reader = MemReader::new(b"bulk console1 heapSnapshot 0:".to_vec());
+        match read_colon_packet(&mut reader).unwrap() {
+            Packet::Bulk { data, .. } => assert!(data.is_empty()),
+            Packet::Json(_) => panic!("expected a bulk packet"),
+        }
+    }
+
+    #[test]
+    fn colon_packet_rejects_malformed_header() {
+        let mut reader = MemReader::new(b"not-a-length:{}".to_vec());
+        assert!(read_colon_packet(&mut reader).is_err());
+    }
+
+    #[test]
+    fn ws_frame_unmasks_client_payload() {
+        // "hi" (0x68, 0x69) masked with key 0x00 0xff 0x00 0xff.
+        let mut reader = MemReader::new(vec!(0x81, 0x82, 0x00, 0xff, 0x00, 0xff,
+                                              0x68 ^ 0x00, 0x69 ^ 0xff));
+        let payload = read_ws_frame(&mut reader).unwrap();
+        assert_eq!(payload.as_slice(), b"hi");
+    }
+
+    #[test]
+    fn ws_frame_round_trips_unmasked_server_frame() {
+        let mut writer = MemWriter::new();
+        write_ws_frame(&mut writer, OP_TEXT, b"hello").unwrap();
+        let mut reader = MemReader::new(writer.into_inner());
+        let payload = read_ws_frame(&mut reader).unwrap();
+        assert_eq!(payload.as_slice(), b"hello");
+    }
+}
@@ -0,0 +1,135 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![crate_name = "plugins"]
+#![crate_type = "dylib"]
+
+#![feature(plugin_registrar, quote)]
+
+//! Compiler plugins shared across components. Currently provides
+//! `#[derive(ActorMessage)]`, which turns an enum of devtools message
+//! variants into the dispatch table and wire-reply boilerplate that
+//! actors (see `devtools::actors`) would otherwise hand-write per
+//! message type.
+
+extern crate syntax;
+extern crate rustc;
+
+use syntax::ast::{Item, ItemEnum, MetaItem, MetaNameValue, LitStr};
+use syntax::codemap::Span;
+use syntax::ext::base::{ExtCtxt, Annotatable};
+use syntax::ext::deriving::generic::MultiDecorator;
+use syntax::parse::token;
+use syntax::ptr::P;
+use rustc::plugin::Registry;
+
+#[plugin_registrar]
+pub fn plugin_registrar(reg: &mut Registry) {
+    reg.register_syntax_extension(token::intern("derive_ActorMessage"),
+                                   MultiDecorator(box expand_actor_message));
+}
+
+/// Expands `#[derive(ActorMessage)]` on an enum whose variants each carry a
+/// `#[msg_type = "..."]` attribute, e.g.:
+///
+/// ```ignore
+/// #[derive(ActorMessage)]
+/// enum TabMessage {
+///     #[msg_type = "startListeners"]
+///     StartListeners,
+///     #[msg_type = "stopListeners"]
+///     StopListeners,
+/// }
+/// ```
+///
+/// Generates two methods used by `Actor::handle_message` implementations in
+/// place of a hand-written `match msg_type.as_slice() { ... }`:
+///
+/// - `parse(msg_type: &str) -> Option<Self>`, the incoming half of the
+///   dispatch table; and
+/// - `reply(&self, from: &str) -> Json`, the `{"from": ..., "type": ...}`
+///   wire reply each of the enum's messages sends back verbatim.
+fn expand_actor_message(cx: &mut ExtCtxt,
+                         span: Span,
+                         _mitem: &MetaItem,
+                         item: &Annotatable,
+                         push: &mut FnMut(Annotatable)) {
+    let item: &P<Item> = match *item {
+        Annotatable::Item(ref item) => item,
+        _ => {
+            cx.span_err(span, "`derive(ActorMessage)` only applies to enums");
+            return;
+        }
+    };
+
+    let (enum_ident, variants) = match item.node {
+        ItemEnum(ref def, _) => (item.ident, &def.variants),
+        _ => {
+            cx.span_err(span, "`derive(ActorMessage)` only applies to enums");
+            return;
+        }
+    };
+
+    // Pull the `#[msg_type = "..."]` string off each variant; a variant
+    // missing the attribute is a usage error in the deriving crate, not
+    // something we can recover from here.
+    let arms: Vec<(token::InternedString, token::InternedString)> = variants.iter().map(|variant| {
+        let msg_type = variant.node.attrs.iter().filter_map(|attr| {
+            match attr.node.value.node {
+                MetaNameValue(ref name, ref lit) if name.get() == "msg_type" => {
+                    match lit.node {
+                        LitStr(ref s, _) => Some(s.clone()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        }).next();
+
+        let msg_type = msg_type.unwrap_or_else(|| {
+            cx.span_err(variant.span, "variants of an `ActorMessage` enum need `#[msg_type = \"...\"]`");
+            token::intern_and_get_ident("")
+        });
+
+        (variant.node.name.name.as_str(), msg_type)
+    }).collect();
+
+    let parse_arms = arms.iter().map(|&(ref variant, ref msg_type)| {
+        quote_arm!(cx, $msg_type => ::std::option::Option::Some($enum_ident::$variant),)
+    }).collect::<Vec<_>>();
+
+    let reply_arms = arms.iter().map(|&(ref variant, ref msg_type)| {
+        quote_arm!(cx,
+            $enum_ident::$variant => {
+                let mut reply: ::serialize::json::Object = ::collections::treemap::TreeMap::new();
+                reply.insert("from".to_string(), ::serialize::json::ToJson::to_json(from));
+                reply.insert("type".to_string(),
+                             ::serialize::json::ToJson::to_json(&$msg_type.to_string()));
+                ::serialize::json::Json::Object(reply)
+            })
+    }).collect::<Vec<_>>();
+
+    let item = quote_item!(cx,
+        impl $enum_ident {
+            /// The incoming half of the dispatch table: map a wire `type`
+            /// string to the variant it names, or `None` for an unknown one.
+            pub fn parse(msg_type: &str) -> ::std::option::Option<$enum_ident> {
+                match msg_type {
+                    $parse_arms
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            /// The common `{"from": ..., "type": ...}` reply every message
+            /// in this enum sends back.
+            pub fn reply(&self, from: &str) -> ::serialize::json::Json {
+                match *self {
+                    $reply_arms
+                }
+            }
+        }
+    ).unwrap();
+
+    push(Annotatable::Item(item));
+}